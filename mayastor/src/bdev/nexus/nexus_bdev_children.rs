@@ -24,16 +24,21 @@
 
 use futures::future::join_all;
 
-use crate::bdev::{
-    bdev_lookup_by_name,
-    nexus::{
-        self,
-        nexus_bdev::{bdev_create, bdev_destroy, Nexus, NexusState},
-        nexus_channel::DREvent,
-        nexus_child::{ChildState, NexusChild},
-        nexus_label::NexusLabel,
-        Error,
+use crate::{
+    bdev::{
+        bdev_lookup_by_name,
+        nexus::{
+            self,
+            nexus_bdev::{bdev_create, bdev_destroy, Nexus, NexusState},
+            nexus_channel::DREvent,
+            nexus_child::{ChildState, NexusChild},
+            nexus_io_policy::IoConsistencyPolicy,
+            nexus_label::NexusLabel,
+            nexus_majority,
+            Error,
+        },
     },
+    subsys::Config,
 };
 
 impl Nexus {
@@ -53,6 +58,7 @@ impl Nexus {
                 ))
             })
             .for_each(drop);
+        self.bump_generation();
     }
 
     /// register a single child the nexus, only allowed during the nexus init
@@ -67,6 +73,7 @@ impl Nexus {
         ));
 
         self.child_count += 1;
+        self.bump_generation();
 
         Ok(name)
     }
@@ -117,9 +124,38 @@ impl Nexus {
                 // the nexus until brought online.
 
                 child.state = ChildState::Faulted;
+                let child_name = child.name.clone();
                 self.children.push(child);
                 self.child_count += 1;
-                // TODO -- rsync labels
+                self.bump_generation();
+                // only the in-sync children get the new generation; the new
+                // child trails it until a rebuild has copied it over
+                let in_sync: Vec<String> = self
+                    .children
+                    .iter()
+                    .filter(|c| c.state == ChildState::Open)
+                    .map(|c| c.name.clone())
+                    .collect();
+                for name in &in_sync {
+                    self.persist_generation(name).await;
+                }
+
+                // bring the new child's GPT label in line with the rest of
+                // the nexus before it takes part in anything else
+                if let Err(e) = self.update_child_labels().await {
+                    error!(
+                        "{}: failed to reconcile labels for {}: {}",
+                        self.name, child_name, e
+                    );
+                }
+
+                if let Err(e) = self.start_rebuild(&child_name, None) {
+                    error!(
+                        "{}: failed to start rebuild of {}: {}",
+                        self.name, child_name, e
+                    );
+                }
+
                 Ok(self.set_state(NexusState::Degraded))
             }
             Err(e) => {
@@ -150,6 +186,7 @@ impl Nexus {
 
         let mut child = self.children.remove(idx);
         self.child_count -= 1;
+        self.bump_generation();
         child.destroy().await?;
         Ok(())
     }
@@ -166,6 +203,7 @@ impl Nexus {
             return Err(Error::NotFound);
         }
 
+        self.bump_generation();
         self.reconfigure(DREvent::ChildOffline).await;
         Ok(self.set_state(NexusState::Degraded))
     }
@@ -186,8 +224,18 @@ impl Nexus {
                 ))
             } else {
                 child.open(self.size)?;
+                // the child was closed (and thus possibly stale), so it may
+                // not rejoin the IO path until a rebuild proves it in sync
+                child.state = ChildState::Faulted;
                 self.reconfigure(DREvent::ChildOnline).await;
-                //TODO should be rebuilding
+
+                if let Err(e) = self.start_rebuild(name, None) {
+                    error!(
+                        "{}: failed to start rebuild of {}: {}",
+                        self.name, name, e
+                    );
+                }
+
                 Ok(self.set_state(NexusState::Degraded))
             }
         } else {
@@ -204,6 +252,7 @@ impl Nexus {
 
         if let Some(child) = self.children.iter_mut().find(|c| c.name == name) {
             child.state = ChildState::Faulted;
+            self.bump_generation();
             self.reconfigure(DREvent::ChildFault).await;
             Ok(self.set_state(NexusState::Degraded))
         } else {
@@ -236,8 +285,14 @@ impl Nexus {
         false
     }
 
-    /// try to open all the child devices
-    pub(crate) fn try_open_children(&mut self) -> Result<(), nexus::Error> {
+    /// try to open all the child devices. Before reporting the resulting
+    /// state, this reconciles persisted generations across the children
+    /// that opened: a child left behind by a crash -- one whose persisted
+    /// generation trails the rest -- is flagged for rebuild instead of
+    /// trusted as a valid member.
+    pub(crate) async fn try_open_children(
+        &mut self,
+    ) -> Result<NexusState, nexus::Error> {
         if self.children.is_empty()
             || self.children.iter().any(|c| c.bdev.is_none())
         {
@@ -261,6 +316,8 @@ impl Nexus {
         self.bdev.set_block_len(blk_size);
 
         let size = self.size;
+        let total = self.children.len();
+        let policy = self.io_consistency_policy();
 
         let (open, error): (Vec<_>, Vec<_>) = self
             .children
@@ -268,25 +325,68 @@ impl Nexus {
             .map(|c| c.open(size))
             .partition(Result::is_ok);
 
-        // depending on IO consistency policies, we might be able to go online
-        // even if one of the children failed to open. This is work is not
-        // completed yet so we fail the registration all together for now.
-
+        // depending on the configured IO consistency policy, we may be able
+        // to come up `Degraded` even if some of the children failed to
+        // open, as long as enough of them did to satisfy quorum.
         if !error.is_empty() {
-            open.into_iter()
-                .map(Result::unwrap)
-                .map(|name| {
-                    if let Some(child) =
-                        self.children.iter_mut().find(|c| c.name == name)
-                    {
-                        let _ = child.close();
-                    } else {
-                        error!("{}: child opened but found!", self.name());
-                    }
-                })
-                .for_each(drop);
+            if open.len() < policy.min_required(total) {
+                error!(
+                    "{}: only {}/{} children opened, quorum requires {}",
+                    self.name,
+                    open.len(),
+                    total,
+                    policy.min_required(total)
+                );
 
-            return Err(Error::NexusIncomplete);
+                open.into_iter()
+                    .map(Result::unwrap)
+                    .map(|name| {
+                        if let Some(child) =
+                            self.children.iter_mut().find(|c| c.name == name)
+                        {
+                            let _ = child.close();
+                        } else {
+                            error!("{}: child opened but found!", self.name());
+                        }
+                    })
+                    .for_each(drop);
+
+                return Err(Error::NexusIncomplete);
+            }
+
+            warn!(
+                "{}: {}/{} children opened, coming up degraded under quorum \
+                 policy",
+                self.name,
+                open.len(),
+                total
+            );
+
+            let opened: Vec<String> =
+                open.into_iter().map(Result::unwrap).collect();
+            let faulted: Vec<String> = self
+                .children
+                .iter()
+                .filter(|c| !opened.contains(&c.name))
+                .map(|c| c.name.clone())
+                .collect();
+
+            for name in &faulted {
+                if let Some(child) =
+                    self.children.iter_mut().find(|c| &c.name == name)
+                {
+                    child.state = ChildState::Faulted;
+                }
+            }
+
+            for name in &faulted {
+                if let Err(e) = self.start_rebuild(name, None) {
+                    error!(
+                        "{}: failed to schedule rebuild of {}: {}",
+                        self.name, name, e
+                    );
+                }
+            }
         }
 
         self.children
@@ -302,38 +402,76 @@ impl Nexus {
                 }
             })
             .for_each(drop);
-        Ok(())
+
+        if let Err(e) = self.reconcile_generations().await {
+            error!(
+                "{}: failed to reconcile child generations: {}",
+                self.name, e
+            );
+        }
+
+        if self.children.iter().any(|c| c.state != ChildState::Open) {
+            Ok(self.set_state(NexusState::Degraded))
+        } else {
+            Ok(self.set_state(NexusState::Online))
+        }
     }
 
     /// read labels from the children devices, we fail the operation if:
     ///
     /// (1) a child does not have valid label
-    /// (2) if any label does not match the label of the first child
+    /// (2) if a label diverges from the rest, it is rewritten to match the
+    /// authoritative one rather than failing the operation
 
     pub async fn update_child_labels(&mut self) -> Result<NexusLabel, Error> {
-        let mut futures = Vec::new();
-        self.children
-            .iter_mut()
-            .map(|child| futures.push(child.probe_label()))
-            .for_each(drop);
+        let names: Vec<String> =
+            self.children.iter().map(|c| c.name.clone()).collect();
+        let futures = self.children.iter_mut().map(|child| child.probe_label());
+        let results = join_all(futures).await;
 
-        let (ret, err): (Vec<_>, Vec<_>) =
-            join_all(futures).await.into_iter().partition(Result::is_ok);
-        if !err.is_empty() {
+        let mut labels: Vec<(String, NexusLabel)> = Vec::new();
+        for (name, result) in names.into_iter().zip(results.into_iter()) {
+            match result {
+                Ok(label) => labels.push((name, label)),
+                Err(e) => error!(
+                    "{}: failed to probe GPT label on {}: {}",
+                    self.name, name, e
+                ),
+            }
+        }
+
+        if labels.len() < self.children.len() {
             return Err(Error::Internal(
                 "failed to probe all child labels".into(),
             ));
         }
 
-        let mut ret: Vec<NexusLabel> =
-            ret.into_iter().map(Result::unwrap).collect();
-
-        // verify that all labels are equal
-        if ret.iter().skip(1).any(|e| e != &ret[0]) {
-            return Err(Error::Invalid("GPT labels differ".into()));
+        // the authoritative label is whichever one is carried by the most
+        // children; a tie falls back to whichever was observed first.
+        let authoritative = labels[nexus_majority::majority_index(&labels)]
+            .1
+            .clone();
+
+        for (name, label) in &labels {
+            if label != &authoritative {
+                warn!(
+                    "{}: child {} carries a divergent GPT label, rewriting it",
+                    self.name, name
+                );
+                if let Some(child) =
+                    self.children.iter_mut().find(|c| &c.name == name)
+                {
+                    if let Err(e) = child.write_label(&authoritative).await {
+                        error!(
+                            "{}: failed to reconcile GPT label on {}: {}",
+                            self.name, name, e
+                        );
+                    }
+                }
+            }
         }
 
-        Ok(ret.pop().unwrap())
+        Ok(authoritative)
     }
 
     /// The nexus is allowed to be smaller then the underlying child devices
@@ -355,4 +493,29 @@ impl Nexus {
             .for_each(drop);
         blockcnt
     }
+
+    /// the IO consistency policy configured for this nexus, controlling how
+    /// many children must open successfully for it to come up and how the
+    /// quorum is evaluated on a partial open.
+    pub(crate) fn io_consistency_policy(&self) -> IoConsistencyPolicy {
+        Config::get().nexus_opts.io_consistency_policy
+    }
+
+    /// true when enough children are currently `Open` to satisfy this
+    /// nexus' configured serving floor -- the same `min_required` count
+    /// that gates `try_open_children`, re-evaluated against the *current*
+    /// child set rather than the one present at open time, so a child
+    /// faulted later (e.g. by `fault_child`, or flagged stale by `reconcile_generations`)
+    /// is reflected here too. Surfaced through [`crate::bdev::nexus::nexus_status::NexusStatus`]
+    /// for a control plane to act on; see the `nexus_io_policy` module docs
+    /// for why nothing in this tree enforces it directly against reads or
+    /// writes.
+    pub(crate) fn meets_serving_quorum(&self) -> bool {
+        let open = self
+            .children
+            .iter()
+            .filter(|c| c.state == ChildState::Open)
+            .count();
+        open >= self.io_consistency_policy().min_required(self.children.len())
+    }
 }
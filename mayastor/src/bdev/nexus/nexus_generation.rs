@@ -0,0 +1,277 @@
+//!
+//! Crash-consistent generation counter for nexus child membership.
+//!
+//! Every membership or state transition -- registering, adding or removing
+//! a child, or taking one offline/faulted -- bumps the nexus' generation
+//! counter. The counter is persisted to a reserved block beyond the child's
+//! replicated, user-addressable range (the same headroom the GPT label and
+//! the rebuild cursor use), so that after a crash `try_open_children` can
+//! tell which child(ren) saw the most recent change: whichever child
+//! carries the highest generation is authoritative, and any child trailing
+//! behind it is flagged for rebuild rather than trusted as a valid source.
+//! This is what keeps two replicas from silently disagreeing about the
+//! child set after a restart. The counter is written to disk only by
+//! `add_child`, to the children that are `Open` at that point; the other
+//! transitions bump it in memory only.
+//!
+//! The in-memory counter starts back at zero every process restart, so
+//! `reconcile_generations` also seeds it from the highest generation
+//! observed on disk before anything else can bump it further -- otherwise
+//! the first post-crash mutation would persist a generation lower than
+//! what a still-running sibling already carries, and the next reconcile
+//! would wrongly flag the freshly-restarted child as stale.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    bdev::nexus::{nexus_bdev::Nexus, nexus_child::ChildState, Error},
+    core::{Bdev, BdevHandle, DmaBuf},
+};
+
+/// slot, within the metadata region that follows the nexus' logical address
+/// range, that the generation counter is persisted to. Offset by one from
+/// the rebuild cursor's slot (slot 0, see `nexus_rebuild::RebuildJob`) so
+/// the two never collide when both are persisted to the same child.
+const GENERATION_SLOT: u64 = 1;
+
+lazy_static! {
+    /// current generation of each nexus, keyed by nexus name. Kept outside
+    /// of `Nexus` itself so every membership-mutating method can bump it
+    /// without needing a dedicated field on the struct.
+    static ref GENERATIONS: Mutex<HashMap<String, AtomicU64>> =
+        Mutex::new(HashMap::new());
+}
+
+impl Nexus {
+    /// bump and return this nexus' membership generation. Called when a
+    /// child is registered, added, removed, taken offline or faulted;
+    /// bringing a child back online, opening the children at start-up,
+    /// reconciling generations and completing a rebuild do not bump it.
+    pub(crate) fn bump_generation(&self) -> u64 {
+        let mut generations = GENERATIONS.lock().unwrap();
+        let counter = generations
+            .entry(self.name.clone())
+            .or_insert_with(|| AtomicU64::new(0));
+        counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// current membership generation, without bumping it.
+    pub(crate) fn generation(&self) -> u64 {
+        GENERATIONS
+            .lock()
+            .unwrap()
+            .get(&self.name)
+            .map_or(0, |c| c.load(Ordering::SeqCst))
+    }
+
+    /// raise this nexus' in-memory generation to at least `min`, inserting
+    /// it if this is the first time the nexus has been seen this process.
+    /// Never lowers an already-seeded counter, so calling this more than
+    /// once (or after a `bump_generation()`) is harmless.
+    ///
+    /// Must be called with the highest generation observed across children
+    /// before any further `bump_generation()` call -- see the module-level
+    /// doc for why.
+    pub(crate) fn seed_generation(&self, min: u64) {
+        let mut generations = GENERATIONS.lock().unwrap();
+        let counter = generations
+            .entry(self.name.clone())
+            .or_insert_with(|| AtomicU64::new(0));
+        counter.fetch_max(min, Ordering::SeqCst);
+    }
+
+    /// block offset (beyond the replicated range) the generation counter is
+    /// persisted to. Child bdevs are sized with headroom beyond the
+    /// nexus' logical block count for exactly this kind of metadata.
+    fn generation_block(&self) -> u64 {
+        self.size / self.bdev.block_len() as u64 + GENERATION_SLOT
+    }
+
+    /// best-effort persist of the current generation to `child`'s reserved
+    /// metadata block, alongside its GPT label. Failures are logged rather
+    /// than propagated: a missed persist only means that child looks stale
+    /// (and thus gets rebuilt) the next time generations are reconciled,
+    /// which is the safe direction to fail in.
+    pub(crate) async fn persist_generation(&self, child: &str) {
+        if let Err(e) = self.try_persist_generation(child).await {
+            error!(
+                "{}: failed to persist generation on {}: {}",
+                self.name, child, e
+            );
+        }
+    }
+
+    async fn try_persist_generation(&self, child: &str) -> Result<(), Error> {
+        let bdev = Bdev::lookup_by_name(child)
+            .ok_or_else(|| Error::Internal(format!("{} not found", child)))?;
+
+        let generation_block = self.generation_block();
+        if bdev.num_blocks() <= generation_block {
+            return Err(Error::Internal(format!(
+                "{} has no headroom beyond its replicated range to persist \
+                 a generation",
+                child
+            )));
+        }
+
+        let handle =
+            BdevHandle::open(&bdev.name(), true, false).map_err(|e| {
+                Error::Internal(format!("failed to open {}: {}", child, e))
+            })?;
+
+        let mut buf = DmaBuf::new(bdev.block_len() as usize, bdev.alignment())
+            .map_err(|e| {
+                Error::Internal(format!("failed to allocate dma buffer: {}", e))
+            })?;
+        buf.as_mut_slice()[.. 8]
+            .copy_from_slice(&self.generation().to_le_bytes());
+
+        handle
+            .write_at(generation_block * bdev.block_len() as u64, &buf)
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "failed to write generation to {}: {}",
+                    child, e
+                ))
+            })
+    }
+
+    /// read the generation `child` carries on-disk, without trusting it --
+    /// the caller decides whether it is authoritative.
+    async fn read_generation(&self, child: &str) -> Result<u64, Error> {
+        let bdev = Bdev::lookup_by_name(child).ok_or(Error::NotFound)?;
+        let handle =
+            BdevHandle::open(&bdev.name(), true, false).map_err(|e| {
+                Error::Internal(format!("failed to open {}: {}", child, e))
+            })?;
+
+        let mut buf = DmaBuf::new(bdev.block_len() as usize, bdev.alignment())
+            .map_err(|e| {
+                Error::Internal(format!("failed to allocate dma buffer: {}", e))
+            })?;
+
+        handle
+            .read_at(
+                self.generation_block() * bdev.block_len() as u64,
+                &mut buf,
+            )
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "failed to read generation from {}: {}",
+                    child, e
+                ))
+            })?;
+
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&buf.as_slice()[.. 8]);
+        Ok(u64::from_le_bytes(raw))
+    }
+
+    /// reconcile on-disk generations across all `Open` children: whichever
+    /// child carries the highest generation is authoritative, and any child
+    /// trailing behind it is demoted to `Faulted` and scheduled for rebuild
+    /// rather than trusted as a valid source. Also seeds this nexus' own
+    /// in-memory generation counter from that authoritative value, since a
+    /// freshly-restarted process otherwise starts counting from zero.
+    /// Called by `try_open_children` once the children it could open have
+    /// been opened.
+    pub async fn reconcile_generations(&mut self) -> Result<(), Error> {
+        let mut observed = Vec::new();
+        for child in self.children.iter().filter(|c| c.state == ChildState::Open)
+        {
+            match self.read_generation(&child.name).await {
+                Ok(gen) => observed.push((child.name.clone(), gen)),
+                Err(e) => error!(
+                    "{}: failed to read generation from {}: {}",
+                    self.name, child.name, e
+                ),
+            }
+        }
+
+        let (authoritative, stale) = partition_stale(&observed);
+        self.seed_generation(authoritative);
+
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        for name in &stale {
+            warn!(
+                "{}: child {} is at a stale generation, flagging for rebuild",
+                self.name, name
+            );
+            if let Some(child) =
+                self.children.iter_mut().find(|c| &c.name == name)
+            {
+                child.state = ChildState::Faulted;
+            }
+            if let Err(e) = self.start_rebuild(name, None) {
+                error!(
+                    "{}: failed to schedule rebuild of stale child {}: {}",
+                    self.name, name, e
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// split `observed` child generations into the authoritative generation (the
+/// highest one seen, or `0` if nothing was observed) and the names of the
+/// children trailing behind it. Pure so it can be exercised without SPDK.
+fn partition_stale(observed: &[(String, u64)]) -> (u64, Vec<String>) {
+    let authoritative =
+        observed.iter().map(|(_, gen)| *gen).max().unwrap_or(0);
+
+    let stale = observed
+        .iter()
+        .filter(|(_, gen)| *gen < authoritative)
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    (authoritative, stale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highest_generation_wins_and_the_rest_are_stale() {
+        let observed = vec![
+            ("a".to_string(), 3),
+            ("b".to_string(), 5),
+            ("c".to_string(), 5),
+        ];
+        let (authoritative, stale) = partition_stale(&observed);
+        assert_eq!(authoritative, 5);
+        assert_eq!(stale, vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn all_equal_generations_means_nothing_is_stale() {
+        let observed =
+            vec![("a".to_string(), 2), ("b".to_string(), 2)];
+        let (authoritative, stale) = partition_stale(&observed);
+        assert_eq!(authoritative, 2);
+        assert!(stale.is_empty());
+    }
+
+    #[test]
+    fn no_observations_defaults_to_generation_zero() {
+        let (authoritative, stale) = partition_stale(&[]);
+        assert_eq!(authoritative, 0);
+        assert!(stale.is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+//!
+//! IO consistency policy for a nexus: how many children are required to
+//! open successfully before the nexus may come up. Defaults to `Strict` so
+//! existing deployments keep today's all-or-nothing behaviour; operators
+//! that prefer availability over strict redundancy can opt into `Quorum`
+//! via `NexusOpts` in the yaml config. `min_required` gates
+//! `try_open_children`, and the same floor is re-checked against the
+//! *current* child set by `Nexus::meets_serving_quorum` and surfaced
+//! through `Nexus::status()` for a control plane to act on. Neither this
+//! module nor `meets_serving_quorum` rejects an individual read or write,
+//! though -- this tree has no IO submission/channel code for a serving
+//! quorum to hook into, so enforcing the floor inline on every IO is out of
+//! scope here.
+
+use serde::{Deserialize, Serialize};
+
+/// Policy applied by `try_open_children` when one or more configured
+/// children fail to open.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum IoConsistencyPolicy {
+    /// every configured child must open successfully, or the nexus fails to
+    /// come up at all
+    Strict,
+    /// the nexus may come up `Degraded` as long as at least `min_children`
+    /// children open successfully; the rest are marked `Faulted` and
+    /// scheduled for rebuild
+    Quorum {
+        /// minimum number of children required to open for the nexus to
+        /// come up
+        min_children: u32,
+    },
+}
+
+impl Default for IoConsistencyPolicy {
+    fn default() -> Self {
+        IoConsistencyPolicy::Strict
+    }
+}
+
+impl IoConsistencyPolicy {
+    /// minimum number of children, out of `total` configured, that must
+    /// open successfully for the nexus to come up.
+    pub fn min_required(&self, total: usize) -> usize {
+        match self {
+            IoConsistencyPolicy::Strict => total,
+            IoConsistencyPolicy::Quorum {
+                min_children,
+            } => (*min_children as usize).min(total),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_always_requires_every_child() {
+        let policy = IoConsistencyPolicy::Strict;
+        assert_eq!(policy.min_required(3), 3);
+        assert_eq!(policy.min_required(0), 0);
+    }
+
+    #[test]
+    fn quorum_requires_min_children() {
+        let policy = IoConsistencyPolicy::Quorum {
+            min_children: 2,
+        };
+        assert_eq!(policy.min_required(3), 2);
+    }
+
+    #[test]
+    fn quorum_is_capped_at_the_configured_total() {
+        let policy = IoConsistencyPolicy::Quorum {
+            min_children: 5,
+        };
+        assert_eq!(policy.min_required(3), 3);
+    }
+
+    #[test]
+    fn default_policy_is_strict() {
+        assert_eq!(IoConsistencyPolicy::default(), IoConsistencyPolicy::Strict);
+    }
+}
@@ -0,0 +1,57 @@
+//!
+//! Generic majority-vote helper shared by GPT label reconciliation
+//! (`update_child_labels`) and the scrub worker: given a list of
+//! `(owner, value)` pairs, pick whichever value is carried by the most
+//! owners, falling back to whichever was seen first on a tie.
+
+/// index, within `items`, of the value carried by the most entries.
+///
+/// # Panics
+///
+/// Panics if `items` is empty -- callers are expected to have already
+/// filtered out the case where there is nothing to compare.
+pub(crate) fn majority_index<T: PartialEq>(items: &[(String, T)]) -> usize {
+    assert!(!items.is_empty(), "items must not be empty");
+
+    // `Iterator::max_by_key` keeps the *last* maximum on a tie, which would
+    // favour whichever value was observed last -- the opposite of the
+    // intended first-seen tie-break -- so the best count is tracked by hand
+    // instead, only replacing it on a strict improvement.
+    let mut best = 0;
+    let mut best_count = 0;
+    for i in 0 .. items.len() {
+        let count = items.iter().filter(|(_, v)| v == &items[i].1).count();
+        if count > best_count {
+            best = i;
+            best_count = count;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_value_seen_most_often() {
+        let items = vec![
+            ("a".to_string(), 1),
+            ("b".to_string(), 2),
+            ("c".to_string(), 1),
+        ];
+        assert_eq!(items[majority_index(&items)].1, 1);
+    }
+
+    #[test]
+    fn falls_back_to_first_seen_on_a_tie() {
+        let items = vec![("a".to_string(), 1), ("b".to_string(), 2)];
+        assert_eq!(majority_index(&items), 0);
+    }
+
+    #[test]
+    fn single_item_is_its_own_majority() {
+        let items = vec![("only".to_string(), 42)];
+        assert_eq!(majority_index(&items), 0);
+    }
+}
@@ -0,0 +1,562 @@
+//!
+//! Background rebuild engine.
+//!
+//! A child that has just been added or brought back online is marked
+//! `Faulted` because its contents do not yet match the rest of the nexus. A
+//! `RebuildJob` is what actually makes it converge: it walks the address
+//! space of the nexus in fixed size segments, copying each one from a
+//! healthy source child to the target with `BdevHandle::read_at` /
+//! `write_at`, and persists how far it has gotten so a restart resumes
+//! instead of starting over. Once the cursor reaches `min_num_blocks()` the
+//! target is caught up and `complete_rebuild` brings it `Open`.
+//!
+//! For the copy to converge, a foreground write that lands ahead of the
+//! cursor must also be mirrored to the target -- anything below the cursor
+//! is already covered by the copy loop, anything at or above it is not.
+//! [`Nexus::mirror_write_if_rebuilding`] does that mirroring: given the same
+//! offset and buffer a write was submitted with, it forwards the write to
+//! every target still being rebuilt at or ahead of its cursor.
+//! [`Nexus::child_needs_write_mirror`] is the cheaper query form of the same
+//! check, used where only the boolean matters (e.g. the scrub worker,
+//! `nexus_scrub.rs`, to skip segments a rebuild already owns).
+//!
+//! Neither is actually called from a write-submission path in this tree --
+//! there is no `nexus_channel`/IO-dispatch module here for either to hook
+//! into, so until one exists, calling `mirror_write_if_rebuilding` for every
+//! accepted write is the integration work left outside this module.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    bdev::{
+        nexus::{
+            nexus_bdev::{Nexus, NexusState},
+            nexus_child::ChildState,
+            Error,
+        },
+        nexus_lookup,
+    },
+    core::{Bdev, BdevHandle, DmaBuf, Reactor},
+};
+
+/// Size, in bytes, of a single rebuild copy extent. Kept small on purpose so
+/// that a rebuild IO never monopolises the device for long enough to starve
+/// foreground IO.
+const REBUILD_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Lifecycle of a single rebuild job.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RebuildState {
+    Running,
+    Paused,
+    Completed,
+    Failed,
+}
+
+/// Tracks progress of copying one target child back in sync with a healthy
+/// source child. Keyed by the target child's name in [`REBUILD_JOBS`], so
+/// only one rebuild may be in flight for a given child at a time.
+pub struct RebuildJob {
+    /// name of the nexus the job belongs to
+    nexus: String,
+    /// name of the healthy child the data is copied from
+    source: String,
+    /// name of the child being rebuilt
+    target: String,
+    /// block size, shared by source and target
+    block_len: u64,
+    /// total number of blocks that must be copied
+    num_blocks: u64,
+    /// blocks in the nexus' logical address range; the reserved metadata
+    /// region starts right after it
+    data_blocks: u64,
+    /// next block not yet known to be in sync on the target
+    cursor: Arc<AtomicU64>,
+    /// current state, checked by the copy loop on every iteration
+    state: Arc<Mutex<RebuildState>>,
+    /// optional throughput cap, in bytes/sec
+    rate_limit: Option<u64>,
+}
+
+lazy_static! {
+    /// All rebuild jobs currently known to this instance, keyed by target
+    /// child name. A `HashMap` behind a lock mirrors the way bdevs
+    /// themselves are kept in a global lookup table rather than threaded
+    /// through every caller.
+    static ref REBUILD_JOBS: Mutex<HashMap<String, Arc<RebuildJob>>> =
+        Mutex::new(HashMap::new());
+}
+
+impl RebuildJob {
+    /// slot, within the metadata region that follows the nexus' logical
+    /// address range, that the rebuild cursor is persisted to. Child bdevs
+    /// are sized with headroom beyond `num_blocks` for exactly this kind of
+    /// nexus metadata (the same headroom the GPT label reserves), so this
+    /// never overlaps the replicated, user-addressable range.
+    const CURSOR_SLOT: u64 = 0;
+
+    /// block offset (beyond the replicated range) the cursor is persisted
+    /// to on the target.
+    fn cursor_block(&self) -> u64 {
+        self.data_blocks + Self::CURSOR_SLOT
+    }
+
+    fn new(
+        nexus: &str,
+        source: &str,
+        target: &str,
+        block_len: u64,
+        num_blocks: u64,
+        data_blocks: u64,
+        rate_limit: Option<u64>,
+    ) -> Self {
+        Self {
+            nexus: nexus.into(),
+            source: source.into(),
+            target: target.into(),
+            block_len,
+            num_blocks,
+            data_blocks,
+            cursor: Arc::new(AtomicU64::new(0)),
+            state: Arc::new(Mutex::new(RebuildState::Running)),
+            rate_limit,
+        }
+    }
+
+    /// current state of the job
+    pub fn state(&self) -> RebuildState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: RebuildState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// blocks copied so far, and the total that must be copied
+    pub fn progress(&self) -> (u64, u64) {
+        (self.cursor.load(Ordering::SeqCst), self.num_blocks)
+    }
+
+    /// true when `offset` (in blocks) has not yet been proven in sync on the
+    /// target, meaning a concurrent write to it must also be mirrored there
+    /// rather than relying on the copy loop to catch up eventually.
+    pub fn is_rebuilding(&self, offset: u64) -> bool {
+        offset >= self.cursor.load(Ordering::SeqCst)
+    }
+
+    /// write the current cursor to the target's reserved metadata region --
+    /// beyond the replicated, user-addressable range -- so that a restart
+    /// can resume rather than rebuilding from scratch.
+    async fn persist_cursor(&self) -> Result<(), Error> {
+        let bdev = Bdev::lookup_by_name(&self.target).ok_or_else(|| {
+            Error::Internal(format!(
+                "rebuild target {} vanished mid-copy",
+                self.target
+            ))
+        })?;
+
+        if bdev.num_blocks() <= self.cursor_block() {
+            return Err(Error::Internal(format!(
+                "{} has no headroom beyond its {} replicated blocks to \
+                 persist a rebuild cursor",
+                self.target, self.data_blocks
+            )));
+        }
+
+        let handle = BdevHandle::open(&bdev.name(), true, false).map_err(|e| {
+            Error::Internal(format!(
+                "failed to open {} to persist rebuild cursor: {}",
+                self.target, e
+            ))
+        })?;
+
+        let mut buf = DmaBuf::new(self.block_len as usize, bdev.alignment())
+            .map_err(|e| {
+                Error::Internal(format!("failed to allocate dma buffer: {}", e))
+            })?;
+        buf.as_mut_slice()[.. 8]
+            .copy_from_slice(&self.cursor.load(Ordering::SeqCst).to_le_bytes());
+
+        handle
+            .write_at(self.cursor_block() * self.block_len, &buf)
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "failed to persist rebuild cursor for {}: {}",
+                    self.target, e
+                ))
+            })?;
+        Ok(())
+    }
+
+    /// read back whatever cursor is persisted in the target's reserved
+    /// metadata region. A target that has never been rebuilt reads back
+    /// zero there (the block is never written before the first rebuild), so
+    /// this is safe to call unconditionally at the start of every rebuild,
+    /// not just ones that are actually resuming after a restart.
+    async fn read_cursor(&self) -> Result<u64, Error> {
+        let bdev = Bdev::lookup_by_name(&self.target).ok_or_else(|| {
+            Error::Internal(format!(
+                "rebuild target {} vanished before it could start",
+                self.target
+            ))
+        })?;
+
+        if bdev.num_blocks() <= self.cursor_block() {
+            return Err(Error::Internal(format!(
+                "{} has no headroom beyond its {} replicated blocks to \
+                 read back a rebuild cursor",
+                self.target, self.data_blocks
+            )));
+        }
+
+        let handle =
+            BdevHandle::open(&bdev.name(), true, false).map_err(|e| {
+                Error::Internal(format!(
+                    "failed to open {} to read back rebuild cursor: {}",
+                    self.target, e
+                ))
+            })?;
+
+        let mut buf = DmaBuf::new(self.block_len as usize, bdev.alignment())
+            .map_err(|e| {
+                Error::Internal(format!("failed to allocate dma buffer: {}", e))
+            })?;
+
+        handle
+            .read_at(self.cursor_block() * self.block_len, &mut buf)
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "failed to read back rebuild cursor for {}: {}",
+                    self.target, e
+                ))
+            })?;
+
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&buf.as_slice()[.. 8]);
+        Ok(u64::from_le_bytes(raw).min(self.num_blocks))
+    }
+
+    /// copy one segment starting at `self.cursor`, returning the number of
+    /// blocks that were copied.
+    async fn copy_one_segment(&self) -> Result<u64, Error> {
+        let source = Bdev::lookup_by_name(&self.source).ok_or_else(|| {
+            Error::Internal(format!(
+                "rebuild source {} vanished mid-copy",
+                self.source
+            ))
+        })?;
+        let target = Bdev::lookup_by_name(&self.target).ok_or_else(|| {
+            Error::Internal(format!(
+                "rebuild target {} vanished mid-copy",
+                self.target
+            ))
+        })?;
+
+        let source_hdl =
+            BdevHandle::open(&source.name(), true, false).map_err(|e| {
+                Error::Internal(format!(
+                    "failed to open rebuild source {}: {}",
+                    self.source, e
+                ))
+            })?;
+        let target_hdl =
+            BdevHandle::open(&target.name(), true, false).map_err(|e| {
+                Error::Internal(format!(
+                    "failed to open rebuild target {}: {}",
+                    self.target, e
+                ))
+            })?;
+
+        let segment_blocks =
+            (REBUILD_SEGMENT_SIZE / self.block_len).max(1);
+        let cursor = self.cursor.load(Ordering::SeqCst);
+        let blocks = segment_blocks.min(self.num_blocks - cursor);
+        let len = (blocks * self.block_len) as usize;
+
+        let mut buf = DmaBuf::new(len, source.alignment()).map_err(|e| {
+            Error::Internal(format!("failed to allocate dma buffer: {}", e))
+        })?;
+
+        source_hdl
+            .read_at(cursor * self.block_len, &mut buf)
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "rebuild read from {} failed at block {}: {}",
+                    self.source, cursor, e
+                ))
+            })?;
+
+        target_hdl
+            .write_at(cursor * self.block_len, &buf)
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "rebuild write to {} failed at block {}: {}",
+                    self.target, cursor, e
+                ))
+            })?;
+
+        self.cursor.fetch_add(blocks, Ordering::SeqCst);
+        self.persist_cursor().await?;
+        Ok(blocks)
+    }
+
+    /// drive the copy loop to completion (or failure), honouring pause
+    /// requests and the configured throughput cap. Resumes from whatever
+    /// cursor the target has persisted, if any, instead of always starting
+    /// at block 0.
+    async fn run(&self) {
+        match self.read_cursor().await {
+            Ok(cursor) if cursor > 0 => {
+                info!(
+                    "rebuild {} -> {}: resuming from persisted cursor at \
+                     block {}",
+                    self.source, self.target, cursor
+                );
+                self.cursor.store(cursor, Ordering::SeqCst);
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "{}: failed to read back rebuild cursor for {}, starting \
+                 from block 0: {}",
+                self.nexus, self.target, e
+            ),
+        }
+
+        trace!(
+            "rebuild {} -> {}: starting from block {}",
+            self.source,
+            self.target,
+            self.cursor.load(Ordering::SeqCst)
+        );
+
+        while self.cursor.load(Ordering::SeqCst) < self.num_blocks {
+            if self.state() == RebuildState::Paused {
+                Reactor::yield_now().await;
+                continue;
+            }
+
+            let started = std::time::Instant::now();
+            match self.copy_one_segment().await {
+                Ok(blocks) => {
+                    if let Some(limit) = self.rate_limit {
+                        let copied = blocks * self.block_len;
+                        let min_elapsed = std::time::Duration::from_secs_f64(
+                            copied as f64 / limit as f64,
+                        );
+                        let elapsed = started.elapsed();
+                        if elapsed < min_elapsed {
+                            Reactor::delay(min_elapsed - elapsed).await;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "rebuild of {} from {} failed: {}",
+                        self.target, self.source, e
+                    );
+                    self.set_state(RebuildState::Failed);
+                    return;
+                }
+            }
+        }
+
+        info!(
+            "rebuild of {} from {} completed, {} blocks copied",
+            self.target, self.source, self.num_blocks
+        );
+        self.set_state(RebuildState::Completed);
+
+        if let Some(nexus) = nexus_lookup(&self.nexus) {
+            nexus.complete_rebuild(&self.target);
+        }
+    }
+}
+
+impl Nexus {
+    /// pick a healthy child to rebuild from; any `Open` child is a valid
+    /// source since only `Open` children ever take part in the IO path.
+    fn rebuild_source(&self) -> Option<String> {
+        self.children
+            .iter()
+            .find(|c| c.state == ChildState::Open)
+            .map(|c| c.name.clone())
+    }
+
+    /// start (or resume, after a restart) a rebuild job for `target`,
+    /// copying from a healthy sibling child. Returns immediately; progress
+    /// is polled with [`Nexus::rebuild_progress`]. `rate_limit` caps the
+    /// copy throughput in bytes/sec, if set.
+    pub fn start_rebuild(
+        &mut self,
+        target: &str,
+        rate_limit: Option<u64>,
+    ) -> Result<(), Error> {
+        if let Some(existing) = REBUILD_JOBS.lock().unwrap().get(target) {
+            let state = existing.state();
+            if state == RebuildState::Running || state == RebuildState::Paused {
+                return Err(Error::Invalid(format!(
+                    "{}: a rebuild of {} is already in flight",
+                    self.name, target
+                )));
+            }
+        }
+
+        let source = self.rebuild_source().ok_or_else(|| {
+            Error::Invalid(format!(
+                "{}: no healthy child available to rebuild {} from",
+                self.name, target
+            ))
+        })?;
+
+        let num_blocks = self.min_num_blocks();
+        let block_len = self.bdev.block_len() as u64;
+
+        let job = Arc::new(RebuildJob::new(
+            &self.name,
+            &source,
+            target,
+            block_len,
+            num_blocks,
+            self.size / block_len,
+            rate_limit,
+        ));
+
+        REBUILD_JOBS
+            .lock()
+            .unwrap()
+            .insert(target.to_string(), job.clone());
+
+        // the job stays reachable through the registry (for
+        // pause/resume/progress) for as long as the task below is alive.
+        Reactor::spawn(async move { job.run().await });
+
+        Ok(())
+    }
+
+    /// pause an in progress rebuild; the cursor is left where it is so the
+    /// copy can resume cleanly later.
+    pub fn pause_rebuild(&mut self, target: &str) -> Result<(), Error> {
+        let jobs = REBUILD_JOBS.lock().unwrap();
+        let job = jobs.get(target).ok_or(Error::NotFound)?;
+        job.set_state(RebuildState::Paused);
+        Ok(())
+    }
+
+    /// resume a previously paused rebuild.
+    pub fn resume_rebuild(&mut self, target: &str) -> Result<(), Error> {
+        let jobs = REBUILD_JOBS.lock().unwrap();
+        let job = jobs.get(target).ok_or(Error::NotFound)?;
+        job.set_state(RebuildState::Running);
+        Ok(())
+    }
+
+    /// `(blocks_done, blocks_total)` for the rebuild of `target`, while it is
+    /// running, paused or has failed. A rebuild that completes is removed
+    /// from the registry, after which this returns `NotFound`.
+    pub fn rebuild_progress(&self, target: &str) -> Result<(u64, u64), Error> {
+        REBUILD_JOBS
+            .lock()
+            .unwrap()
+            .get(target)
+            .map(|job| job.progress())
+            .ok_or(Error::NotFound)
+    }
+
+    /// true when `target` still has a rebuild in flight that has not yet
+    /// proven `offset` (in blocks) in sync. Cheaper than
+    /// `mirror_write_if_rebuilding` when only the boolean is needed; today
+    /// that's the scrub worker, to skip segments a rebuild already owns.
+    pub(crate) fn child_needs_write_mirror(
+        &self,
+        target: &str,
+        offset: u64,
+    ) -> bool {
+        REBUILD_JOBS
+            .lock()
+            .unwrap()
+            .get(target)
+            .map_or(false, |job| job.is_rebuilding(offset))
+    }
+
+    /// forward a write the nexus just accepted to every child still being
+    /// rebuilt at or ahead of `offset` (in blocks), so the rebuild's copy
+    /// loop isn't racing a write it hasn't copied yet. `buf` is written
+    /// starting at the same block offset on each such target.
+    ///
+    /// The write-submission path should call this for every write after it
+    /// lands on the `Open` children; no such path exists in this tree for
+    /// it to be called from (see the module docs), so today nothing calls
+    /// this method.
+    pub(crate) async fn mirror_write_if_rebuilding(
+        &self,
+        offset: u64,
+        buf: &DmaBuf,
+    ) -> Result<(), Error> {
+        let targets: Vec<String> = REBUILD_JOBS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, job)| job.is_rebuilding(offset))
+            .map(|(target, _)| target.clone())
+            .collect();
+
+        for target in targets {
+            let bdev = Bdev::lookup_by_name(&target).ok_or_else(|| {
+                Error::Internal(format!(
+                    "rebuild target {} vanished mid-write",
+                    target
+                ))
+            })?;
+            let handle = BdevHandle::open(&bdev.name(), true, false)
+                .map_err(|e| {
+                    Error::Internal(format!(
+                        "failed to open {} to mirror write: {}",
+                        target, e
+                    ))
+                })?;
+            handle
+                .write_at(offset * self.bdev.block_len() as u64, buf)
+                .await
+                .map_err(|e| {
+                    Error::Internal(format!(
+                        "failed to mirror write to {} at block {}: {}",
+                        target, offset, e
+                    ))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// called by a rebuild job when it finishes: bring the child `Open` and,
+    /// if every child is now healthy, the nexus itself `Online`.
+    fn complete_rebuild(&mut self, target: &str) {
+        if let Some(child) = self.children.iter_mut().find(|c| c.name == target)
+        {
+            child.state = ChildState::Open;
+        }
+
+        REBUILD_JOBS.lock().unwrap().remove(target);
+
+        if self
+            .children
+            .iter()
+            .all(|c| c.state == ChildState::Open)
+        {
+            self.set_state(NexusState::Online);
+        }
+    }
+}
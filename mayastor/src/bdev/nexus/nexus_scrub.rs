@@ -0,0 +1,300 @@
+//!
+//! Background scrub (read-repair) worker.
+//!
+//! Unlike a rebuild, which copies a single faulted child back in sync, a
+//! scrub assumes every `Open` child is expected to already agree and exists
+//! to catch and correct the cases where that assumption turned out to be
+//! wrong -- silent corruption, a missed write, or anything else that GPT
+//! label reconciliation alone would not notice. It walks the full block
+//! range, reads each extent from every `Open` child, and whenever a block
+//! mismatches across replicas rewrites the out-of-date copies from the
+//! authoritative one. It never touches a block on a child that is currently
+//! being rebuilt at or beyond its rebuild cursor -- that data is known-stale
+//! and is the rebuild job's responsibility, not the scrub's.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    bdev::{
+        nexus::{
+            nexus_bdev::Nexus,
+            nexus_child::ChildState,
+            nexus_majority,
+            Error,
+        },
+        nexus_lookup,
+    },
+    core::{Bdev, BdevHandle, DmaBuf, Reactor},
+};
+
+/// size, in bytes, of a single scrub read/compare extent.
+const SCRUB_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScrubState {
+    Running,
+    Paused,
+    Completed,
+    Failed,
+}
+
+/// a single, nexus-wide scrub pass.
+pub struct ScrubJob {
+    nexus: String,
+    block_len: u64,
+    num_blocks: u64,
+    cursor: AtomicU64,
+    state: Mutex<ScrubState>,
+    rate_limit: Option<u64>,
+}
+
+lazy_static! {
+    /// at most one scrub job per nexus, keyed by nexus name.
+    static ref SCRUB_JOBS: Mutex<HashMap<String, Arc<ScrubJob>>> =
+        Mutex::new(HashMap::new());
+}
+
+impl ScrubJob {
+    fn new(
+        nexus: &str,
+        block_len: u64,
+        num_blocks: u64,
+        rate_limit: Option<u64>,
+    ) -> Self {
+        Self {
+            nexus: nexus.into(),
+            block_len,
+            num_blocks,
+            cursor: AtomicU64::new(0),
+            state: Mutex::new(ScrubState::Running),
+            rate_limit,
+        }
+    }
+
+    /// blocks scrubbed so far, and the total that must be scrubbed.
+    pub fn progress(&self) -> (u64, u64) {
+        (self.cursor.load(Ordering::SeqCst), self.num_blocks)
+    }
+
+    fn state(&self) -> ScrubState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: ScrubState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// read one segment's worth of blocks from `child` at `offset`.
+    async fn read_segment(
+        &self,
+        child: &str,
+        offset: u64,
+        blocks: u64,
+    ) -> Result<DmaBuf, Error> {
+        let bdev = Bdev::lookup_by_name(child)
+            .ok_or_else(|| Error::Internal(format!("{} not found", child)))?;
+        let handle =
+            BdevHandle::open(&bdev.name(), true, false).map_err(|e| {
+                Error::Internal(format!("failed to open {}: {}", child, e))
+            })?;
+
+        let mut buf =
+            DmaBuf::new((blocks * self.block_len) as usize, bdev.alignment())
+                .map_err(|e| {
+                    Error::Internal(format!(
+                        "failed to allocate dma buffer: {}",
+                        e
+                    ))
+                })?;
+
+        handle
+            .read_at(offset * self.block_len, &mut buf)
+            .await
+            .map_err(|e| {
+                Error::Internal(format!(
+                    "scrub read from {} failed at block {}: {}",
+                    child, offset, e
+                ))
+            })?;
+
+        Ok(buf)
+    }
+
+    /// scrub one segment starting at `self.cursor`, comparing every `Open`
+    /// child that is not currently being rebuilt below that point. Mirrors
+    /// the majority-vote approach `update_child_labels` uses for GPT labels:
+    /// whichever content is carried by the most children wins, rather than
+    /// trusting list order -- with only two candidates this degrades to
+    /// "the first one read", which is the best that can be done without a
+    /// third opinion to break the tie.
+    async fn scrub_one_segment(&self, nexus: &Nexus) -> Result<u64, Error> {
+        let segment_blocks = (SCRUB_SEGMENT_SIZE / self.block_len).max(1);
+        let cursor = self.cursor.load(Ordering::SeqCst);
+        let blocks = segment_blocks.min(self.num_blocks - cursor);
+
+        let candidates: Vec<String> = nexus
+            .children
+            .iter()
+            .filter(|c| c.state == ChildState::Open)
+            .filter(|c| !nexus.child_needs_write_mirror(&c.name, cursor))
+            .map(|c| c.name.clone())
+            .collect();
+
+        if candidates.len() < 2 {
+            // nothing to compare against; skip ahead
+            self.cursor.fetch_add(blocks, Ordering::SeqCst);
+            return Ok(blocks);
+        }
+
+        let mut reads = Vec::with_capacity(candidates.len());
+        for name in &candidates {
+            let buf = self.read_segment(name, cursor, blocks).await?;
+            reads.push((name.clone(), buf));
+        }
+
+        // `DmaBuf` itself isn't comparable, so vote on the bytes it carries;
+        // this is the same majority-vote approach `update_child_labels` uses
+        // for GPT labels, just applied to raw segment contents instead.
+        let contents: Vec<(String, &[u8])> = reads
+            .iter()
+            .map(|(name, buf)| (name.clone(), buf.as_slice()))
+            .collect();
+        let authoritative = nexus_majority::majority_index(&contents);
+        let authoritative_name = &reads[authoritative].0;
+
+        for (name, buf) in &reads {
+            if buf.as_slice() == reads[authoritative].1.as_slice() {
+                continue;
+            }
+
+            warn!(
+                "{}: scrub found {} diverging from {} at block {}, repairing",
+                self.nexus, name, authoritative_name, cursor
+            );
+
+            let bdev = Bdev::lookup_by_name(name).ok_or_else(|| {
+                Error::Internal(format!("{} not found", name))
+            })?;
+            let handle = BdevHandle::open(&bdev.name(), true, false)
+                .map_err(|e| {
+                    Error::Internal(format!("failed to open {}: {}", name, e))
+                })?;
+            handle
+                .write_at(cursor * self.block_len, &reads[authoritative].1)
+                .await
+                .map_err(|e| {
+                    Error::Internal(format!(
+                        "failed to repair {} at block {}: {}",
+                        name, cursor, e
+                    ))
+                })?;
+
+            info!(
+                "{}: repaired {} blocks on {} starting at block {}",
+                self.nexus, blocks, name, cursor
+            );
+        }
+
+        self.cursor.fetch_add(blocks, Ordering::SeqCst);
+        Ok(blocks)
+    }
+
+    async fn run(self: Arc<Self>, nexus_name: String) {
+        trace!("{}: scrub starting", self.nexus);
+
+        while self.cursor.load(Ordering::SeqCst) < self.num_blocks {
+            if self.state() == ScrubState::Paused {
+                Reactor::yield_now().await;
+                continue;
+            }
+
+            let nexus = match nexus_lookup(&nexus_name) {
+                Some(nexus) => nexus,
+                None => {
+                    warn!("{}: nexus gone, aborting scrub", self.nexus);
+                    self.set_state(ScrubState::Failed);
+                    return;
+                }
+            };
+
+            let started = Instant::now();
+            match self.scrub_one_segment(&nexus).await {
+                Ok(blocks) => {
+                    if let Some(limit) = self.rate_limit {
+                        let scrubbed = blocks * self.block_len;
+                        let min_elapsed = Duration::from_secs_f64(
+                            scrubbed as f64 / limit as f64,
+                        );
+                        let elapsed = started.elapsed();
+                        if elapsed < min_elapsed {
+                            Reactor::delay(min_elapsed - elapsed).await;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("{}: scrub failed: {}", self.nexus, e);
+                    self.set_state(ScrubState::Failed);
+                    return;
+                }
+            }
+        }
+
+        info!("{}: scrub completed", self.nexus);
+        self.set_state(ScrubState::Completed);
+    }
+}
+
+impl Nexus {
+    /// start a full-range scrub of this nexus, comparing every `Open` child
+    /// and repairing any block that has drifted out of sync. `rate_limit`
+    /// caps the scrub throughput in bytes/sec so it can be confined to idle
+    /// periods.
+    pub fn start_scrub(&mut self, rate_limit: Option<u64>) -> Result<(), Error> {
+        if let Some(existing) = SCRUB_JOBS.lock().unwrap().get(&self.name) {
+            let state = existing.state();
+            if state == ScrubState::Running || state == ScrubState::Paused {
+                return Err(Error::Invalid(format!(
+                    "{}: a scrub is already in flight",
+                    self.name
+                )));
+            }
+        }
+
+        let job = Arc::new(ScrubJob::new(
+            &self.name,
+            self.bdev.block_len() as u64,
+            self.min_num_blocks(),
+            rate_limit,
+        ));
+
+        SCRUB_JOBS
+            .lock()
+            .unwrap()
+            .insert(self.name.clone(), job.clone());
+
+        let nexus_name = self.name.clone();
+        Reactor::spawn(async move { job.run(nexus_name).await });
+
+        Ok(())
+    }
+
+    /// `(blocks_done, blocks_total)` for the scrub in progress, if any.
+    pub fn scrub_progress(&self) -> Result<(u64, u64), Error> {
+        SCRUB_JOBS
+            .lock()
+            .unwrap()
+            .get(&self.name)
+            .map(|job| job.progress())
+            .ok_or(Error::NotFound)
+    }
+}
@@ -0,0 +1,106 @@
+//!
+//! Structured, serializable status of a nexus and its children, for
+//! external control planes that want to poll health without reaching into
+//! internals -- analogous to a cluster-status endpoint that reports
+//! per-node capacity and up/down state.
+
+use serde::{Deserialize, Serialize};
+
+use crate::bdev::nexus::{
+    nexus_bdev::{Nexus, NexusState},
+    nexus_child::{ChildState, NexusChild},
+};
+
+/// Status of a single child, as seen from the nexus.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChildStatus {
+    /// uri the child was created with
+    pub uri: String,
+    /// bdev name backing the child, if its bdev has been found
+    pub name: Option<String>,
+    /// current state of the child
+    pub state: ChildState,
+    /// block size, in bytes
+    pub block_len: u64,
+    /// total number of blocks the child device has
+    pub num_blocks: u64,
+    /// blocks currently usable by the nexus, bounded by the smallest child
+    pub usable_blocks: u64,
+    /// rebuild progress, if a rebuild of this child is running, paused or
+    /// has failed
+    pub rebuild_progress: Option<(u64, u64)>,
+    /// true once the child has been closed and taken out of the IO path
+    pub draining: bool,
+}
+
+impl ChildStatus {
+    fn from_child(child: &NexusChild, usable_blocks: u64) -> Self {
+        let (block_len, num_blocks) = match child.bdev.as_ref() {
+            Some(bdev) => (bdev.block_len() as u64, bdev.num_blocks()),
+            None => (0, 0),
+        };
+
+        Self {
+            uri: child.name.clone(),
+            name: child.bdev.as_ref().map(|bdev| bdev.name()),
+            state: child.state,
+            block_len,
+            num_blocks,
+            usable_blocks,
+            rebuild_progress: None,
+            draining: child.state == ChildState::Closed,
+        }
+    }
+}
+
+/// Status of a nexus and all of its children.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NexusStatus {
+    /// name of the nexus
+    pub name: String,
+    /// current state of the nexus
+    pub state: NexusState,
+    /// per-child status, in the same order as the nexus' children
+    pub children: Vec<ChildStatus>,
+    /// smallest `num_blocks` across all `Open` children
+    pub smallest_child_blocks: u64,
+    /// true when the nexus is `Degraded` or one or more children are not
+    /// `Open`
+    pub degraded: bool,
+    /// true when enough children are currently `Open` to satisfy the
+    /// nexus' configured `IoConsistencyPolicy` serving floor. Note this is
+    /// advisory only: nothing in this tree's IO submission path enforces
+    /// it, so a control plane reading this is responsible for acting on a
+    /// `false` value itself (e.g. refusing further writes out-of-band).
+    pub serving_quorum_met: bool,
+}
+
+impl Nexus {
+    /// report the current status of the nexus and each of its children.
+    pub fn status(&self) -> NexusStatus {
+        let smallest_child_blocks = self.min_num_blocks();
+
+        let children = self
+            .children
+            .iter()
+            .map(|child| {
+                let mut status =
+                    ChildStatus::from_child(child, smallest_child_blocks);
+                if let Ok(progress) = self.rebuild_progress(&child.name) {
+                    status.rebuild_progress = Some(progress);
+                }
+                status
+            })
+            .collect();
+
+        NexusStatus {
+            name: self.name.clone(),
+            state: self.state,
+            children,
+            smallest_child_blocks,
+            degraded: self.state == NexusState::Degraded
+                || self.children.iter().any(|c| c.state != ChildState::Open),
+            serving_quorum_met: self.meets_serving_quorum(),
+        }
+    }
+}